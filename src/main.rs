@@ -19,6 +19,17 @@ use crate::io::{expand_dirs, read_pathbuf_to_mem, write_mem_to_pathbuf};
 use rayon::prelude::*;
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::Rng;
+use rand::RngCore;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::collections::hash_map::DefaultHasher;
+use dashmap::DashSet;
+use siphasher::sip128::{Hasher128, SipHasher13};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::collections::HashMap;
 
 pub mod s3;
 pub mod io;
@@ -51,7 +62,39 @@ struct ArgParser {
     docs_per_jsonl: usize,
 
     #[arg(long)]
-    remove_locals: bool
+    remove_locals: bool,
+
+    #[arg(long, default_value_t=u64::MAX)]
+    max_cell_bytes: u64,
+
+    #[arg(long, default_value_t=1_048_576)]
+    flush_threshold_bytes: usize,
+
+    // Bounds the *total* bytes staged across all num_local_cells per-cell
+    // buffers within a single Rayon task, independent of flush_threshold_bytes
+    // (which only bounds any one cell's buffer). Without this, worst-case
+    // per-task staging memory is num_local_cells * flush_threshold_bytes.
+    #[arg(long, default_value_t=67_108_864)]
+    max_staged_bytes: usize,
+
+    // Note: combined with --dedup, byte-identical reproducibility across
+    // runs is not guaranteed -- which of two duplicate lines survives
+    // depends on Rayon scheduling order, even with the same --seed.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    // Note: duplicate tracking ("seen" fingerprints) is in-memory only and
+    // is not part of the resume manifest. An interrupted coarse pass is redone
+    // in full on resume, so no duplicates are missed, but a resumed run that
+    // skips an already-complete coarse pass reports 0 duplicates removed.
+    #[arg(long)]
+    dedup: bool,
+
+    #[arg(long)]
+    dedup_key: Option<String>,
+
+    #[arg(long)]
+    verify: bool,
 
 }
 
@@ -60,6 +103,71 @@ struct ArgParser {
 =                             UTILITIES.                          =
 =================================================================*/
 
+// Hashes a global seed together with a stable per-unit id (an input path's
+// position for the coarse pass, a cell's filename for the fine pass) so that
+// bucket assignment / in-cell permutation depend only on the seed and the
+// unit being processed, not on which Rayon worker got to it first.
+fn derive_seed<T: Hash>(global_seed: u64, stable_id: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    global_seed.hash(&mut hasher);
+    stable_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn make_rng<T: Hash>(seed: Option<u64>, stable_id: T) -> Box<dyn RngCore> {
+    match seed {
+        Some(s) => Box::new(ChaCha8Rng::seed_from_u64(derive_seed(s, stable_id))),
+        None => Box::new(thread_rng()),
+    }
+}
+
+// 128-bit SipHash fingerprint, either of the raw line bytes or of the string
+// value at `dedup_key` when a line is JSON and that field is present.
+// Returns `None` when `dedup_key` is set but the line isn't JSON or doesn't
+// have that key as a string, so the caller can skip dedup for that line
+// rather than silently mixing full-line fingerprints into a keyed set --
+// otherwise two key-less lines would only ever dedup if byte-identical, and
+// could spuriously collide with a keyed fingerprint from an unrelated line.
+fn dedup_fingerprint(line: &str, dedup_key: &Option<String>) -> Option<u128> {
+    let bytes = match dedup_key {
+        Some(key) => {
+            serde_json::from_str::<serde_json::Value>(line).ok()
+                .and_then(|v| v.get(key).and_then(|f| f.as_str()).map(|s| s.as_bytes().to_vec()))?
+        },
+        None => line.as_bytes().to_vec(),
+    };
+    let mut hasher = SipHasher13::new();
+    hasher.write(&bytes);
+    Some(hasher.finish128().as_u128())
+}
+
+// 128-bit SipHash of a single line, used as the per-line term folded together
+// by `digest_paths` below.
+fn line_fingerprint(line: &str) -> u128 {
+    let mut hasher = SipHasher13::new();
+    hasher.write(line.as_bytes());
+    hasher.finish128().as_u128()
+}
+
+// Order-independent (count, checksum) digest over every line across `paths`:
+// summing the per-line fingerprints mod 2^128 is commutative, so the result
+// is insensitive to shuffling order and only changes if a line is lost,
+// added, or corrupted. Unlike XOR-folding, a wrapping sum doesn't let
+// duplicated/dropped lines cancel out -- e.g. input {A,A,B,B} and a corrupt
+// output {A,A,A,A} have equal XOR-folds (both 0) despite B being silently
+// dropped, but different wrapping sums.
+fn digest_paths(paths: &Vec<PathBuf>) -> (usize, u128) {
+    paths.par_iter()
+        .map(|p| {
+            let contents = read_pathbuf_to_mem(p).unwrap();
+            contents.lines().fold((0usize, 0u128), |(count, checksum), line| {
+                let line = line.unwrap();
+                (count + 1, checksum.wrapping_add(line_fingerprint(&line)))
+            })
+        })
+        .reduce(|| (0, 0), |(c1, s1), (c2, s2)| (c1 + c2, s1.wrapping_add(s2)))
+}
+
 fn build_pbar(num_items: usize, units: &str) -> ProgressBar {
     let mut template = String::from(units);
     template.push_str(" {human_pos}/{human_len} [{elapsed_precise}/{duration_precise}] [{wide_bar:.cyan/blue}]");
@@ -73,6 +181,45 @@ fn build_pbar(num_items: usize, units: &str) -> ProgressBar {
 }
 
 
+/*=================================================================
+=                            RESUME MANIFEST.                     =
+=================================================================*/
+
+// Persisted in `local_cell_storage` so a killed run can resume without
+// redoing work whose local cell files already made it to disk: which input
+// paths have been fully drained into cells, and which cells `finalize_chunks`
+// has already emitted (as a contiguous (start_index, count) range of output
+// files). A path/cell is only recorded here once its data is durably on disk,
+// so a crash can never make resume skip work that wasn't actually persisted.
+// `coarse_pass_complete` is only set once every input has been processed and
+// every cell writer flushed+synced -- the coarse pass is all-or-nothing, so
+// a crash partway through must never be mistaken for a finished pass (see
+// `coarse_shuffle`, which truncates the local cells and restarts from
+// scratch whenever this is still false).
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    consumed_inputs: HashSet<PathBuf>,
+    coarse_pass_complete: bool,
+    finalized_cells: HashMap<PathBuf, (usize, usize)>,
+    next_output_index: usize,
+}
+
+fn manifest_path(local_cell_storage: &PathBuf) -> PathBuf {
+    local_cell_storage.join("manifest.json")
+}
+
+fn load_manifest(path: &PathBuf) -> Manifest {
+    fs::read(path).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn persist_manifest(manifest: &Manifest, path: &PathBuf) {
+    let bytes = serde_json::to_vec(manifest).unwrap();
+    fs::write(path, bytes).unwrap();
+}
+
+
 /*=================================================================
 =                               COARSE-SHUFFLE                    =
 =================================================================*/
@@ -100,13 +247,64 @@ fn build_local_mappers(mapper_loc: &PathBuf, num_local_cells: usize) -> (Vec<Arc
 }
 
 
-fn coarse_shuffle(input_paths: &Vec<PathBuf>, local_cell_storage: &PathBuf, num_local_cells: usize, remove_locals: bool) -> Result<Vec<PathBuf>, Error> {
+// Shared across all coarse_shuffle_single calls when `--dedup` is set: a
+// sharded set of seen fingerprints plus a running count of dropped lines.
+struct DedupState {
+    seen: DashSet<u128>,
+    key: Option<String>,
+    dropped: AtomicUsize,
+}
+
+// Cells are opened in append mode (build_local_mappers), so a coarse pass
+// that only partially completed last time -- some cell bytes already on
+// disk, but no input recorded as consumed, since that's only ever committed
+// once the *entire* pass finishes -- must never be resumed by reprocessing
+// inputs on top of those leftover bytes: that would silently duplicate
+// whatever fraction got written before the crash. Wipe the cell files so a
+// not-yet-complete coarse pass always restarts from a clean slate instead.
+fn clear_local_mappers(local_cell_storage: &PathBuf, num_local_cells: usize) {
+    for i in 0..num_local_cells {
+        fs::remove_file(local_cell_storage.join(format!("local_mapper_{:?}.bin", i))).ok();
+    }
+}
+
+fn coarse_shuffle(input_paths: &Vec<PathBuf>, local_cell_storage: &PathBuf, num_local_cells: usize, remove_locals: bool, flush_threshold_bytes: usize, max_staged_bytes: usize, seed: Option<u64>, dedup: bool, dedup_key: Option<String>, manifest: &Mutex<Manifest>, manifest_path: &PathBuf) -> Result<(Vec<PathBuf>, usize), Error> {
+    // The coarse pass is all-or-nothing: if the last attempt didn't reach
+    // `coarse_pass_complete`, any bytes already in the cells are from an
+    // interrupted run and cannot be trusted. Clear them (and any partial
+    // `consumed_inputs` bookkeeping) and reprocess every input from scratch.
+    {
+        let mut manifest = manifest.lock().unwrap();
+        if !manifest.coarse_pass_complete {
+            clear_local_mappers(local_cell_storage, num_local_cells);
+            manifest.consumed_inputs.clear();
+        }
+    }
+
     let (writers, filenames) = build_local_mappers(local_cell_storage, num_local_cells);
     let pbar = build_pbar(input_paths.len(), "Paths");
+    let dedup_state = if dedup {
+        Some(DedupState { seen: DashSet::new(), key: dedup_key, dropped: AtomicUsize::new(0) })
+    } else {
+        None
+    };
+    // Newly-consumed inputs are collected here, not written to the manifest
+    // as they complete: their lines only become durable once every cell
+    // writer below is flushed+synced, so recording "consumed" any earlier
+    // would let a crash lose buffered lines while resume still skips the
+    // input that produced them.
+    let newly_consumed: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
     input_paths.par_iter()
-        .for_each(|p| {
-            coarse_shuffle_single(p, &writers).unwrap();
+        .enumerate()
+        .for_each(|(idx, p)| {
+            if manifest.lock().unwrap().consumed_inputs.contains(p) {
+                pbar.inc(1);
+                return;
+            }
 
+            coarse_shuffle_single(p, &writers, flush_threshold_bytes, max_staged_bytes, seed, idx, dedup_state.as_ref()).unwrap();
+
+            newly_consumed.lock().unwrap().push(p.clone());
             if remove_locals {
                 fs::remove_file(p.clone()).unwrap();
             }
@@ -114,21 +312,87 @@ fn coarse_shuffle(input_paths: &Vec<PathBuf>, local_cell_storage: &PathBuf, num_
     });
 
     writers.par_iter()
-        .for_each(|writer| writer.lock().unwrap().flush().unwrap());
-    Ok(filenames)
+        .for_each(|writer| {
+            let mut writer = writer.lock().unwrap();
+            writer.flush().unwrap();
+            writer.get_ref().sync_all().unwrap();
+        });
+
+    // Only now, with every line durably on disk, record the inputs that
+    // produced them as consumed and mark the whole pass complete -- from
+    // this point on, a resume can trust the cells and skip straight to
+    // finalizing them instead of clearing and redoing the coarse pass.
+    {
+        let mut manifest = manifest.lock().unwrap();
+        manifest.consumed_inputs.extend(newly_consumed.into_inner().unwrap());
+        manifest.coarse_pass_complete = true;
+        persist_manifest(&manifest, manifest_path);
+    }
+
+    let dropped = dedup_state.map_or(0, |ds| ds.dropped.into_inner());
+    Ok((filenames, dropped))
 }
 
 
-fn coarse_shuffle_single(path: &PathBuf, writers: &Vec<Arc<Mutex<BufWriter<File>>>>) -> Result<(), Error> {
+// Stages each line into a per-cell buffer (thread-local to this Rayon task)
+// instead of locking the cell's writer on every line, and only takes the
+// lock to drain a buffer once it crosses `flush_threshold_bytes`. Turns N
+// lock acquisitions per line into roughly one per flush threshold per cell.
+// `flush_threshold_bytes` only bounds any *one* cell's buffer, though -- with
+// `num_local_cells` buffers live per task, total staged memory could reach
+// num_local_cells * flush_threshold_bytes (e.g. ~1 GiB at the defaults) per
+// concurrent Rayon task. `max_staged_bytes` bounds the *sum* of all of this
+// task's buffers: whenever staging a line brings the total to or over it, the
+// single fullest buffer is flushed right after (it's the one closest to
+// `flush_threshold_bytes` anyway, so this rarely costs more lock contention
+// than the per-cell threshold would have on its own).
+// `stable_id` (the input path's position, or a cell's filename when called
+// during recursive re-bucketing) makes bucket assignment reproducible under
+// a `--seed`, independent of Rayon's scheduling order. `dedup_state`, when
+// set, drops any line whose fingerprint has already been seen.
+fn coarse_shuffle_single<T: Hash>(path: &PathBuf, writers: &Vec<Arc<Mutex<BufWriter<File>>>>, flush_threshold_bytes: usize, max_staged_bytes: usize, seed: Option<u64>, stable_id: T, dedup_state: Option<&DedupState>) -> Result<(), Error> {
     let num_local_cells = writers.len();
     let contents = read_pathbuf_to_mem(path).unwrap();
-    let mut rng = rand::thread_rng();
+    let mut rng = make_rng(seed, stable_id);
+    let mut buffers: Vec<Vec<u8>> = vec![Vec::new(); num_local_cells];
+    let mut staged_bytes: usize = 0;
     for line in contents.lines() {
         let line = line.unwrap();
-        let mut line = line.into_bytes();
-        line.push(b'\n');
+        if let Some(ds) = dedup_state {
+            // A line with no fingerprint (dedup_key set but missing/non-JSON)
+            // passes through untouched instead of being matched against --
+            // or silently folded into -- the whole-line-hash namespace.
+            if let Some(fingerprint) = dedup_fingerprint(&line, &ds.key) {
+                if !ds.seen.insert(fingerprint) {
+                    ds.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+        }
         let idx = rng.gen::<usize>() as usize % num_local_cells;
-        writers[idx].lock().unwrap().write_all(&line).unwrap();
+        let buffer = &mut buffers[idx];
+        let added = line.len() + 1;
+        buffer.extend_from_slice(line.as_bytes());
+        buffer.push(b'\n');
+        staged_bytes += added;
+        if buffer.len() >= flush_threshold_bytes {
+            staged_bytes -= buffer.len();
+            writers[idx].lock().unwrap().write_all(buffer).unwrap();
+            buffer.clear();
+        } else if staged_bytes >= max_staged_bytes {
+            let (fullest_idx, _) = buffers.iter().enumerate().max_by_key(|(_, b)| b.len()).unwrap();
+            if !buffers[fullest_idx].is_empty() {
+                staged_bytes -= buffers[fullest_idx].len();
+                writers[fullest_idx].lock().unwrap().write_all(&buffers[fullest_idx]).unwrap();
+                buffers[fullest_idx].clear();
+            }
+        }
+    }
+
+    for (idx, buffer) in buffers.iter().enumerate() {
+        if !buffer.is_empty() {
+            writers[idx].lock().unwrap().write_all(buffer).unwrap();
+        }
     }
     Ok(())
 }
@@ -138,38 +402,211 @@ fn coarse_shuffle_single(path: &PathBuf, writers: &Vec<Arc<Mutex<BufWriter<File>
 =                            FINE-SHUFFLE.                        =
 =================================================================*/
 
-fn finalize_chunks(filenames: Vec<PathBuf>, output: &PathBuf, docs_per_jsonl: usize, remove_locals: bool) -> Result<usize, Error> {
+// How many top-level cells to finalize between manifest checkpoints. Bounds
+// the number of full manifest rewrites to roughly num_cells / this, instead
+// of one per cell, while still keeping the amount of work a crash can lose
+// to resuming bounded and small.
+const MANIFEST_CHECKPOINT_EVERY: usize = 16;
+
+fn finalize_chunks(filenames: Vec<PathBuf>, output: &PathBuf, docs_per_jsonl: usize, remove_locals: bool, max_cell_bytes: u64, num_local_cells: usize, flush_threshold_bytes: usize, max_staged_bytes: usize, seed: Option<u64>, manifest: &Mutex<Manifest>, manifest_path: &PathBuf) -> Result<usize, Error> {
     let pbar = build_pbar(filenames.len(), "Local Cells");
-    let counter = AtomicUsize::new(0);
-    let output_file_count = AtomicUsize::new(0);
-    filenames.par_iter()
-        .for_each(|filename| {
-            let contents = read_pathbuf_to_mem(&filename).unwrap();
-            let mut lines: Vec<String> = contents.lines().map(|line| line.unwrap()).collect();
-            lines.shuffle(&mut thread_rng());
-            for chunk in lines.chunks(docs_per_jsonl) {
-                write_chunk(chunk, &output, &counter, &output_file_count).unwrap();
+
+    // A cell's temp directory only holds real content while that cell is
+    // mid-finalization; anything left behind here is from a run that was
+    // killed before the atomic rename below, and is safe to discard.
+    let tmp_root = output.join(".finalize_tmp");
+    fs::remove_dir_all(&tmp_root).ok();
+    fs::create_dir_all(&tmp_root)?;
+
+    // Processed in batches (Phase 1: parallel compute, Phase 2: sequential
+    // publish) rather than computing every cell's output up front, so at
+    // most one batch's worth of tmp output -- and, with remove_locals, still-
+    // unremoved source -- sits on disk at a time, instead of the whole
+    // dataset's. Batches are contiguous, fixed-order slices of `filenames`,
+    // so this bounds disk usage without disturbing Phase 2's determinism.
+    let batch_size = rayon::current_num_threads().max(1);
+    let mut next_index = manifest.lock().unwrap().next_output_index;
+    let mut completed_since_checkpoint = 0usize;
+
+    for batch in filenames.chunks(batch_size) {
+        // Phase 1 (parallel): shuffle/split every not-yet-finalized cell in
+        // this batch into its own private tmp dir and count its output
+        // files. No shared output-index counter is touched here, so this
+        // phase can run in whatever order Rayon likes without affecting
+        // which file a cell's chunks end up as -- that's decided entirely by
+        // Phase 2 below. `.collect()` on a `par_iter().map()` preserves the
+        // batch's original order in the result Vec regardless of which
+        // closure actually finishes first.
+        let work: Vec<Option<(PathBuf, usize, Vec<(PathBuf, bool)>)>> = batch.par_iter()
+            .map(|filename| {
+                if manifest.lock().unwrap().finalized_cells.contains_key(filename) {
+                    return None;
+                }
+
+                let tmp_dir = tmp_root.join(filename.file_stem().unwrap());
+                fs::create_dir_all(&tmp_dir).unwrap();
+                let local_counter = AtomicUsize::new(0);
+                // `finalize_cell` never deletes the cell's own source file(s)
+                // -- it only reports which paths `remove_locals` would have
+                // removed -- so that cleanup can be deferred until it's
+                // actually safe (see below).
+                let (count, to_remove) = finalize_cell(filename, &tmp_dir, docs_per_jsonl, remove_locals, max_cell_bytes, num_local_cells, flush_threshold_bytes, max_staged_bytes, seed, &local_counter, 0).unwrap();
+                Some((tmp_dir, count, to_remove))
+            })
+            .collect();
+
+        // Phase 2 (sequential, walking this batch in its fixed order --
+        // never completion order): each cell's start index is a running
+        // offset from `next_output_index`, so a cell's output filenames
+        // depend only on its position in `filenames` and its own
+        // (seed+content-derived) count. With a seed, a cell that is not split
+        // (see `finalize_cell`) always produces byte-identical output files,
+        // names included. A cell split under `max_cell_bytes` does not: its
+        // lines are re-bucketed in the order they sit in the cell file, and
+        // with several input paths that order depends on when each coarse-
+        // pass task flushed into it.
+        for (filename, entry) in batch.iter().zip(work.into_iter()) {
+            let (tmp_dir, count, to_remove) = match entry {
+                Some(v) => v,
+                None => { pbar.inc(1); continue; }
+            };
+
+            // Publish every file this cell produced via rename. Renaming
+            // within the same filesystem is atomic per-file, and this cell
+            // gets no manifest entry until every rename below has
+            // happened, so a crash mid-publish leaves at most a
+            // partially-published cell with no entry in the manifest --
+            // resume redoes the whole cell rather than risking duplicates.
+            let start = next_index;
+            next_index += count;
+            for local_idx in 0..count {
+                let tmp_path = tmp_dir.join(format!("shuffled_doc_{:08}.jsonl.gz", local_idx));
+                let final_path = output.join(format!("shuffled_doc_{:08}.jsonl.gz", start + local_idx));
+                fs::rename(&tmp_path, &final_path).unwrap();
             }
-            if remove_locals {
-                fs::remove_file(filename.clone()).unwrap();
+            fs::remove_dir_all(&tmp_dir).ok();
+
+            {
+                let mut manifest = manifest.lock().unwrap();
+                manifest.finalized_cells.insert(filename.clone(), (start, count));
+                manifest.next_output_index = manifest.next_output_index.max(next_index);
+
+                if remove_locals && !to_remove.is_empty() {
+                    // The cell's source file(s) are about to be deleted, so
+                    // this record must be durably on disk *before* that
+                    // happens -- otherwise a crash in between leaves the
+                    // source gone with no manifest entry, and resume can
+                    // neither find it to reprocess nor know this cell's
+                    // already-published output is already accounted for.
+                    // This forces a persist outside the normal checkpoint
+                    // cadence, but only for cells actually being cleaned up.
+                    persist_manifest(&manifest, manifest_path);
+                    completed_since_checkpoint = 0;
+                } else {
+                    completed_since_checkpoint += 1;
+                    if completed_since_checkpoint >= MANIFEST_CHECKPOINT_EVERY {
+                        completed_since_checkpoint = 0;
+                        persist_manifest(&manifest, manifest_path);
+                    }
+                }
             }
+
+            for (path, is_dir) in &to_remove {
+                if *is_dir { fs::remove_dir(path).ok(); } else { fs::remove_file(path).ok(); }
+            }
+
             pbar.inc(1);
-        });
+        }
+    }
+
+    fs::remove_dir_all(&tmp_root).ok();
+
+    // Final unconditional checkpoint so the last partial batch isn't lost.
+    persist_manifest(&manifest.lock().unwrap(), manifest_path);
+
+    Ok(manifest.lock().unwrap().finalized_cells.values().map(|(_, count)| count).sum())
+}
+
 
-    Ok(output_file_count.into_inner())
+// Bounds how many times a cell can be recursively re-bucketed. Without this,
+// a cell that can never shrink below `max_cell_bytes` -- a single line larger
+// than the budget, or `--max-cell-bytes 0` -- would recurse forever, spawning
+// `num_local_cells` sub-cells on disk at every level until the stack overflows.
+const MAX_SPLIT_DEPTH: usize = 16;
+
+// Recursively re-buckets any cell over `max_cell_bytes` into `num_local_cells`
+// sub-cells (same independent-uniform assignment as coarse_shuffle) until each
+// leaf fits in memory or `MAX_SPLIT_DEPTH` is reached, then shuffles and
+// writes it as-is. Composing independent uniform bucketing with a full
+// Fisher-Yates shuffle at the leaf still yields a uniform permutation
+// overall. The cell's own filename is the stable id used to derive its seed,
+// so a given cell always shuffles the same way.
+// `output` is the top-level cell's private temp directory, and `counter` is
+// local to that directory (not the global output index), so the whole
+// recursion tree writes uniquely-named chunks into it; `finalize_chunks`
+// reserves a real index range and publishes them only once this returns.
+// Returns the number of output files produced, plus the source paths that
+// `remove_locals` would delete (file or dir, in child-before-parent order)
+// -- never deleted here, since the caller must only do that once this
+// cell's output is durably published and manifest-recorded; deleting the
+// source any earlier and then crashing would make resume unrecoverable.
+fn finalize_cell(filename: &PathBuf, output: &PathBuf, docs_per_jsonl: usize, remove_locals: bool, max_cell_bytes: u64, num_local_cells: usize, flush_threshold_bytes: usize, max_staged_bytes: usize, seed: Option<u64>, counter: &AtomicUsize, depth: usize) -> Result<(usize, Vec<(PathBuf, bool)>), Error> {
+    let file_size = fs::metadata(filename)?.len();
+    if file_size > max_cell_bytes && depth < MAX_SPLIT_DEPTH {
+        let sub_dir = filename.parent().unwrap().join(format!("{}_split_{}", filename.file_stem().unwrap().to_string_lossy(), depth));
+        // Sub-cells are opened in append mode, so any left over from an
+        // earlier, unfinished attempt at this cell are cleared first.
+        fs::remove_dir_all(&sub_dir).ok();
+        fs::create_dir_all(&sub_dir)?;
+        let (writers, sub_filenames) = build_local_mappers(&sub_dir, num_local_cells);
+        coarse_shuffle_single(filename, &writers, flush_threshold_bytes, max_staged_bytes, seed, filename.clone(), None)?;
+        writers.iter().for_each(|writer| writer.lock().unwrap().flush().unwrap());
+
+        let results: Vec<(usize, Vec<(PathBuf, bool)>)> = sub_filenames.par_iter()
+            .map(|sub_filename| finalize_cell(sub_filename, output, docs_per_jsonl, remove_locals, max_cell_bytes, num_local_cells, flush_threshold_bytes, max_staged_bytes, seed, counter, depth + 1).unwrap())
+            .collect();
+        let count: usize = results.iter().map(|(c, _)| c).sum();
+        let mut to_remove: Vec<(PathBuf, bool)> = results.into_iter().flat_map(|(_, r)| r).collect();
+
+        if remove_locals {
+            // The original oversized cell and its split directory are only
+            // safe to remove after every sub-cell above them is removed, so
+            // they're appended last.
+            to_remove.push((filename.clone(), false));
+            to_remove.push((sub_dir.clone(), true));
+        }
+        Ok((count, to_remove))
+    } else {
+        let contents = read_pathbuf_to_mem(filename)?;
+        let mut lines: Vec<String> = contents.lines().map(|line| line.unwrap()).collect();
+        // Line order in a cell file depends on when each coarse-pass task
+        // flushed into it; sorting first makes a seeded shuffle depend only
+        // on the cell's contents.
+        if seed.is_some() {
+            lines.sort();
+        }
+        lines.shuffle(&mut make_rng(seed, filename.clone()));
+        let mut count = 0;
+        for chunk in lines.chunks(docs_per_jsonl) {
+            write_chunk(chunk, output, counter).unwrap();
+            count += 1;
+        }
+        let to_remove = if remove_locals { vec![(filename.clone(), false)] } else { Vec::new() };
+        Ok((count, to_remove))
+    }
 }
 
 
-fn write_chunk(chunk: &[String], output: &PathBuf, counter: &AtomicUsize, output_counter: &AtomicUsize) -> Result<Vec<String>, Error> {
-    let output_path = output.clone().join(format!("shuffled_doc_{:08}.jsonl.gz", counter.fetch_add(1, Ordering::SeqCst)));
-    output_counter.fetch_add(1, Ordering::SeqCst);
+fn write_chunk(chunk: &[String], output: &PathBuf, counter: &AtomicUsize) -> Result<usize, Error> {
+    let index = counter.fetch_add(1, Ordering::SeqCst);
+    let output_path = output.clone().join(format!("shuffled_doc_{:08}.jsonl.gz", index));
     let contents: Vec<u8> = chunk.iter()
                    .flat_map(|s| s.as_bytes().iter().chain(std::iter::once(&b'\n')))
                    .cloned()
                    .collect();
 
     write_mem_to_pathbuf(&contents, &output_path).unwrap();
-    Ok(Vec::new())
+    Ok(index)
 }
 
 /*=================================================================
@@ -181,18 +618,183 @@ fn main() {
     let args = ArgParser::parse();
     let paths = expand_dirs(args.input.clone(), None).unwrap();
 
+    fs::create_dir_all(&args.local_cell_storage).unwrap();
+    let manifest_path = manifest_path(&args.local_cell_storage);
+    let manifest = Mutex::new(load_manifest(&manifest_path));
+
+    if args.dedup && args.seed.is_some() {
+        eprintln!("Warning: --seed does not make --dedup output byte-identical across runs -- which duplicate survives depends on Rayon scheduling order.");
+    }
+    if args.dedup && !manifest.lock().unwrap().consumed_inputs.is_empty() {
+        eprintln!("Warning: resuming a --dedup run whose coarse pass already completed -- duplicates were removed by the previous run, so 'duplicates_removed' will undercount.");
+    }
+
+    // Digest the inputs before the coarse pass can remove them (--remove-locals).
+    let input_digest = if args.verify {
+        Some(digest_paths(&paths))
+    } else {
+        None
+    };
+
     println!("Starting coarse shuffle...");
     let start_coarse = Instant::now();
-    let local_cells = coarse_shuffle(&paths, &args.local_cell_storage, args.num_local_cells, args.remove_locals).unwrap();
+    let (local_cells, duplicates_removed) = coarse_shuffle(&paths, &args.local_cell_storage, args.num_local_cells, args.remove_locals, args.flush_threshold_bytes, args.max_staged_bytes, args.seed, args.dedup, args.dedup_key.clone(), &manifest, &manifest_path).unwrap();
     println!("Finished coarse shuffle in {:?} secs", start_coarse.elapsed().as_secs());
+    if args.dedup {
+        println!("Removed {:?} duplicates", duplicates_removed);
+    }
 
     println!("Writing chunks...)");
     let start_chunks = Instant::now();
-    let num_output_files = finalize_chunks(local_cells, &args.output, args.docs_per_jsonl, args.remove_locals).unwrap();
+    let num_output_files = finalize_chunks(local_cells, &args.output, args.docs_per_jsonl, args.remove_locals, args.max_cell_bytes, args.num_local_cells, args.flush_threshold_bytes, args.max_staged_bytes, args.seed, &manifest, &manifest_path).unwrap();
     println!("Finished writing chunks in {:?} secs", start_chunks.elapsed().as_secs());
+    fs::remove_file(&manifest_path).ok();
+
+    if let Some((input_count, input_checksum)) = input_digest {
+        println!("Verifying output is a permutation of input...");
+        let output_paths = expand_dirs(vec![args.output.clone()], None).unwrap();
+        let (output_count, output_checksum) = digest_paths(&output_paths);
+        if args.dedup {
+            // duplicates_removed only reflects this run's coarse pass, so on
+            // a resumed run (see the warning above) this expected_count can
+            // undercount duplicates and make verify spuriously fail.
+            let expected_count = input_count - duplicates_removed;
+            if output_count != expected_count {
+                panic!("Verification failed: expected {:?} output lines after dedup, got {:?}", expected_count, output_count);
+            }
+            println!("Verify ok: {:?} input lines -> {:?} output lines (dedup enabled, checksum equality skipped)", input_count, output_count);
+        } else if input_count != output_count || input_checksum != output_checksum {
+            panic!("Verification failed: input (count={:?}, checksum={:?}) != output (count={:?}, checksum={:?})", input_count, input_checksum, output_count, output_checksum);
+        } else {
+            println!("Verify ok: {:?} lines, checksum {:?} matches", input_count, input_checksum);
+        }
+    }
 
     println!("-------------------------");
-    println!("Finishing data shuffle in {:?} seconds", start_main.elapsed().as_secs());    
+    println!("Finishing data shuffle in {:?} seconds", start_main.elapsed().as_secs());
     println!("Generated {:?} output files", num_output_files);
 
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unique per test (keyed by process id + label) rather than shared, so
+    // tests running concurrently don't collide on the same directory.
+    fn unique_temp_dir(label: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("docshuffle_test_{}_{}", label, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn finalize_cell_recursive_split_preserves_multiset() {
+        let dir = unique_temp_dir("split");
+        let input = dir.join("cell.bin");
+        let lines: Vec<String> = (0..500).map(|i| format!("{{\"id\":{}}}", i)).collect();
+        let contents: Vec<u8> = lines.iter()
+            .flat_map(|l| l.as_bytes().iter().chain(std::iter::once(&b'\n')))
+            .cloned()
+            .collect();
+        write_mem_to_pathbuf(&contents, &input).unwrap();
+
+        let output_dir = dir.join("out");
+        fs::create_dir_all(&output_dir).unwrap();
+        let counter = AtomicUsize::new(0);
+        // A tiny max_cell_bytes forces several levels of recursive splitting.
+        let (count, _to_remove) = finalize_cell(&input, &output_dir, 50, false, 200, 4, 1_048_576, 67_108_864, Some(42), &counter, 0).unwrap();
+        assert!(count > 0);
+
+        let output_paths = expand_dirs(vec![output_dir.clone()], None).unwrap();
+        let (out_count, out_sum) = digest_paths(&output_paths);
+        let (in_count, in_sum) = lines.iter().fold((0usize, 0u128), |(c, s), l| (c + 1, s.wrapping_add(line_fingerprint(l))));
+
+        assert_eq!(out_count, in_count);
+        assert_eq!(out_sum, in_sum);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn dedup_fingerprint_skips_missing_key_instead_of_hashing_whole_line() {
+        let key = Some("id".to_string());
+        assert!(dedup_fingerprint("not json", &key).is_none());
+        assert!(dedup_fingerprint(r#"{"other":1}"#, &key).is_none());
+
+        let a = dedup_fingerprint(r#"{"id":"x","other":1}"#, &key).unwrap();
+        let b = dedup_fingerprint(r#"{"id":"x","other":2}"#, &key).unwrap();
+        assert_eq!(a, b, "same key value should fingerprint the same regardless of other fields");
+
+        assert!(dedup_fingerprint("any line", &None).is_some());
+    }
+
+    #[test]
+    fn coarse_shuffle_single_dedup_drops_exact_duplicates() {
+        let dir = unique_temp_dir("dedup");
+        let input = dir.join("in.bin");
+        write_mem_to_pathbuf(b"a\nb\na\nc\nb\na\n", &input).unwrap();
+
+        let (writers, _filenames) = build_local_mappers(&dir, 4);
+        let ds = DedupState { seen: DashSet::new(), key: None, dropped: AtomicUsize::new(0) };
+        coarse_shuffle_single(&input, &writers, 1_048_576, 67_108_864, Some(7), 0usize, Some(&ds)).unwrap();
+        writers.iter().for_each(|w| w.lock().unwrap().flush().unwrap());
+
+        // 6 lines, 3 distinct values -> 3 dropped as duplicates.
+        assert_eq!(ds.dropped.load(Ordering::SeqCst), 3);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn digest_paths_sum_catches_dropped_and_duplicated_lines() {
+        let dir = unique_temp_dir("digest");
+        let input = dir.join("input.jsonl");
+        let corrupt = dir.join("corrupt.jsonl");
+        write_mem_to_pathbuf(b"A\nA\nB\nB\n", &input).unwrap();
+        write_mem_to_pathbuf(b"A\nA\nA\nA\n", &corrupt).unwrap();
+
+        let (in_count, in_sum) = digest_paths(&vec![input.clone()]);
+        let (out_count, out_sum) = digest_paths(&vec![corrupt.clone()]);
+
+        assert_eq!(in_count, out_count);
+        // An XOR-fold would make these equal (each pair cancels itself out);
+        // the wrapping sum must still tell {A,A,B,B} and {A,A,A,A} apart.
+        assert_ne!(in_sum, out_sum);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn manifest_round_trips_consumed_inputs() {
+        let dir = unique_temp_dir("resume");
+        let mpath = manifest_path(&dir);
+
+        let mut manifest = load_manifest(&mpath);
+        assert!(manifest.consumed_inputs.is_empty());
+
+        let p = PathBuf::from("some/input.jsonl");
+        manifest.consumed_inputs.insert(p.clone());
+        persist_manifest(&manifest, &mpath);
+
+        let reloaded = load_manifest(&mpath);
+        assert!(reloaded.consumed_inputs.contains(&p));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn next_output_index_merges_monotonically_instead_of_overwriting() {
+        // Regression test for the race where a cell that reserved an earlier
+        // range could finish (and persist) after one that reserved a later
+        // range, regressing next_output_index if it were simply overwritten.
+        let mut manifest = Manifest::default();
+        manifest.next_output_index = 10;
+        manifest.next_output_index = manifest.next_output_index.max(5 + 2);
+        assert_eq!(manifest.next_output_index, 10);
+        manifest.next_output_index = manifest.next_output_index.max(10 + 3);
+        assert_eq!(manifest.next_output_index, 13);
+    }
 }
\ No newline at end of file